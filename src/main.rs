@@ -1,9 +1,15 @@
 extern crate minreq;
-use clap::Clap;
-use prometheus_exporter::prometheus::core::{AtomicI64, GenericGauge};
-use prometheus_exporter::{self, prometheus::register_counter, prometheus::register_int_gauge};
+use clap::{Clap, IntoApp};
+use clap_generate::generators::{Bash, Fish, PowerShell, Zsh};
+use prometheus_exporter::prometheus::{Counter, Encoder, HistogramVec, IntGaugeVec, TextEncoder};
+use prometheus_exporter::{
+    self, prometheus::register_counter, prometheus::register_histogram_vec,
+    prometheus::register_int_gauge_vec,
+};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::Write;
 use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,16 +28,60 @@ struct AllBuilds {
 
 #[derive(Debug, Serialize, Deserialize)]
 enum MyError {
-    GenericError(String),
+    /// A failure sending the request or receiving a response, e.g. a timeout or connection
+    /// reset. Worth retrying, since a subsequent attempt may succeed.
+    TransportError(String),
+    /// The request succeeded but the response body could not be parsed as the expected JSON
+    /// shape. Retrying will not help, since Jenkins will keep returning the same body.
+    ParseError(String),
+}
+
+impl MyError {
+    /// Whether a failed attempt is worth retrying.
+    fn is_retriable(&self) -> bool {
+        matches!(self, MyError::TransportError(_))
+    }
 }
 
 impl From<minreq::Error> for MyError {
     fn from(err: minreq::Error) -> Self {
-        MyError::GenericError(format!("Generic error: {:?}", err))
+        MyError::TransportError(format!("Generic error: {:?}", err))
+    }
+}
+
+/// With probability `opts.error_probability`, returns a simulated transport error instead of
+/// making the real request. Only present with the `random-errors` feature, so production
+/// builds can't be affected by it.
+#[cfg(feature = "random-errors")]
+fn maybe_inject_fault(opts: &Opts) -> Option<MyError> {
+    if rand::thread_rng().gen_bool(opts.error_probability.max(0.0).min(1.0)) {
+        Some(MyError::TransportError(
+            "injected fault: simulated transport failure".to_string(),
+        ))
+    } else {
+        None
+    }
+}
+
+/// With probability `opts.error_probability`, mangles an otherwise-successful response to
+/// simulate Jenkins returning corrupted build data. Only present with the `random-errors`
+/// feature.
+#[cfg(feature = "random-errors")]
+fn maybe_mangle_builds(opts: &Opts, builds: AllBuilds) -> AllBuilds {
+    if rand::thread_rng().gen_bool(opts.error_probability.max(0.0).min(1.0)) {
+        AllBuilds { builds: Vec::new() }
+    } else {
+        builds
     }
 }
 
-fn get_job_builds(opts: &Opts, job: &str) -> Result<AllBuilds, MyError> {
+/// Makes a single, unretried attempt at fetching the builds for `job`.
+fn get_job_builds_once(opts: &Opts, job: &str) -> Result<AllBuilds, MyError> {
+    #[cfg(feature = "random-errors")]
+    if let Some(err) = maybe_inject_fault(opts) {
+        return Err(err);
+    }
+
     let host = &opts.jenkins_host;
     let last_builds = opts.last_builds;
     // let url = "https://jenkins.fd.io/job/vpp-verify-master-debian10-x86_64/api/json?tree=builds[number,status,timestamp,id,result]";
@@ -39,11 +89,45 @@ fn get_job_builds(opts: &Opts, job: &str) -> Result<AllBuilds, MyError> {
         "https://{}/job/{}/api/json?tree=builds[number,status,timestamp,id,result,duration]{{,{}}}",
         host, job, last_builds
     );
-    let response = minreq::get(url).with_timeout(opts.req_timeout_sec).send()?;
-    let result = response.json::<AllBuilds>()?;
+    let response = minreq::get(url)
+        .with_timeout(opts.req_timeout_sec)
+        .send()
+        .map_err(|e| MyError::TransportError(format!("Generic error: {:?}", e)))?;
+    let result = response
+        .json::<AllBuilds>()
+        .map_err(|e| MyError::ParseError(format!("Generic error: {:?}", e)))?;
+
+    #[cfg(feature = "random-errors")]
+    let result = maybe_mangle_builds(opts, result);
+
     Ok(result)
 }
 
+/// Fetches the builds for `job`, retrying transport errors with exponential backoff and jitter
+/// up to `opts.max_retries` times. Returns the final result together with how many retries it
+/// took to get there (0 if the first attempt succeeded or failed permanently).
+fn get_job_builds(opts: &Opts, job: &str) -> (Result<AllBuilds, MyError>, i64) {
+    let mut retries = 0;
+    loop {
+        let result = get_job_builds_once(opts, job);
+        let err = match &result {
+            Ok(_) => return (result, retries),
+            Err(e) => e,
+        };
+        if !err.is_retriable() || retries >= opts.max_retries as i64 {
+            return (result, retries);
+        }
+        retries += 1;
+        let backoff_ms = opts
+            .retry_base_ms
+            .saturating_mul(1u64.saturating_shl((retries - 1).min(63) as u32));
+        let jitter = rand::thread_rng().gen_range(0.8..1.2);
+        let delay_ms = ((backoff_ms as f64) * jitter) as u64;
+        let delay_ms = delay_ms.min(opts.retry_max_ms);
+        std::thread::sleep(Duration::from_millis(delay_ms));
+    }
+}
+
 /// This program periodically polls Jenkins jobs that are specified in the parameters,
 /// and exports it for Prometheus
 #[derive(Clone, Clap, Serialize, Deserialize)]
@@ -57,10 +141,36 @@ struct Opts {
     #[clap(long, default_value = "30")]
     req_timeout_sec: u64,
 
+    /// How many times to retry a failed Jenkins API request before giving up on it
+    #[clap(long, default_value = "3")]
+    max_retries: u32,
+
+    /// Base delay, in milliseconds, for the exponential backoff between retries
+    #[clap(long, default_value = "250")]
+    retry_base_ms: u64,
+
+    /// Upper bound, in milliseconds, on the backoff delay between retries
+    #[clap(long, default_value = "5000")]
+    retry_max_ms: u64,
+
+    /// Probability (0.0-1.0), drawn separately for each request attempt, that it fails with a
+    /// simulated transport error, and, drawn again for an attempt that succeeds, that its build
+    /// data is replaced with an empty list, so the exporter's retry/error-counter/gauge-zeroing
+    /// behavior can be exercised in CI without a flaky Jenkins. Only present with the
+    /// `random-errors` feature.
+    #[cfg(feature = "random-errors")]
+    #[clap(long, default_value = "0.0")]
+    error_probability: f64,
+
     /// Poll interval - how often to get the job builds status
     #[clap(short, long, default_value = "1800")]
     poll_interval_sec: u64,
 
+    /// Warn, and count the request as slow, if a single job's Jenkins API request, including any
+    /// retries and the backoff between them, takes longer than this, in milliseconds
+    #[clap(long, default_value = "10000")]
+    slow_request_warn_ms: i64,
+
     /// Bind Prometheus exporter to this address
     #[clap(short, long, default_value = "127.0.0.1:9186")]
     bind_to: std::net::SocketAddr,
@@ -69,6 +179,25 @@ struct Opts {
     #[clap(short, long, default_value = "10")]
     last_builds: usize,
 
+    /// Bucket boundaries, in seconds, for the build duration histogram
+    #[clap(
+        long,
+        default_value = "30,60,300,900,1800,3600",
+        use_delimiter = true
+    )]
+    duration_buckets: Vec<f64>,
+
+    /// Run exactly one poll cycle, write the Prometheus text exposition to this file, and exit
+    /// instead of starting the long-running HTTP exporter. Useful with a cron job or a
+    /// node_exporter textfile collector.
+    #[clap(long)]
+    snapshot_file: Option<String>,
+
+    /// Run exactly one poll cycle and print the Prometheus text exposition to stdout, instead
+    /// of starting the long-running HTTP exporter. Implied by `--snapshot-file`.
+    #[clap(long)]
+    oneshot: bool,
+
     /// Jenkins jobs to monitor. If a single element and it is a filename that exists, load all
     /// options from JSON in it. NB: this overrides anything specified on command line.
     // There's a bit of a history to all that: https://github.com/clap-rs/clap/issues/748
@@ -125,11 +254,30 @@ fn calc_metrics(
     return out;
 }
 
+/// Returns the duration, in seconds, of each build in the inspected window, so they can be fed
+/// into a Prometheus histogram.
+fn calc_build_durations_sec(data: &Result<AllBuilds, MyError>, try_total: usize) -> Vec<f64> {
+    if data.is_err() {
+        return Vec::new();
+    }
+
+    let data = data.as_ref().unwrap();
+    let last_n = match data.builds.windows(try_total).nth(0) {
+        Some(last_n) => last_n,
+        None => return Vec::new(),
+    };
+
+    last_n.iter().map(|b| b.duration as f64 / 1000.0).collect()
+}
+
 #[derive(Clone, Debug, Default)]
 struct AllGaugeData {
     gauges: HashMap<String, HashMap<String, i64>>,
+    durations: HashMap<String, Vec<f64>>,
     req_counter: i64,
     req_err_counter: i64,
+    req_retry_counter: i64,
+    slow_request_counter: i64,
 }
 
 fn get_all_gauge_data(opts: &Opts) -> AllGaugeData {
@@ -139,7 +287,8 @@ fn get_all_gauge_data(opts: &Opts) -> AllGaugeData {
     for job in &opts.jobs {
         let now = SystemTime::now();
         out.req_counter = out.req_counter + 1;
-        let response = get_job_builds(&opts, job);
+        let (response, retries) = get_job_builds(&opts, job);
+        out.req_retry_counter = out.req_retry_counter + retries;
         let elapsed = match now.elapsed() {
             Ok(elapsed) => elapsed.as_millis() as i64,
             Err(e) => {
@@ -151,11 +300,20 @@ fn get_all_gauge_data(opts: &Opts) -> AllGaugeData {
         if response.is_err() {
             out.req_err_counter = out.req_err_counter + 1;
         }
+        if elapsed > opts.slow_request_warn_ms {
+            eprintln!(
+                "WARNING: job {} took {}ms to respond, exceeding slow_request_warn_ms ({}ms)",
+                &job, elapsed, opts.slow_request_warn_ms
+            );
+            out.slow_request_counter = out.slow_request_counter + 1;
+        }
         let metrics = calc_metrics(&response, opts.last_builds, opts.verbose);
         println!(
             "{}: ok {}/ nok {}/ unstable {}/ total {}",
             &job, &metrics["success"], &metrics["failure"], &metrics["unstable"], &metrics["total"]
         );
+        out.durations
+            .insert(job.to_string(), calc_build_durations_sec(&response, opts.last_builds));
         out.gauges.insert(job.to_string(), metrics);
         out.gauges
             .get_mut(job)
@@ -165,7 +323,203 @@ fn get_all_gauge_data(opts: &Opts) -> AllGaugeData {
     out
 }
 
+#[cfg(all(test, feature = "random-errors"))]
+mod random_errors_tests {
+    use super::*;
+
+    #[test]
+    fn fault_injection_moves_err_and_retry_counters() {
+        let opts = Opts::parse_from(&[
+            "jenkins-job-health-exporter",
+            "--error-probability",
+            "0.99",
+            "--max-retries",
+            "2",
+            "--retry-base-ms",
+            "1",
+            "--retry-max-ms",
+            "1",
+            "some-job",
+        ]);
+
+        let data = get_all_gauge_data(&opts);
+
+        assert!(data.req_err_counter > 0, "expected an injected request failure to be counted");
+        assert!(
+            data.req_retry_counter > 0,
+            "expected the injected failure to have been retried"
+        );
+    }
+}
+
+/// The full set of Prometheus metrics this exporter registers, shared between the long-running
+/// HTTP exporter and the one-shot snapshot mode.
+struct Metrics {
+    poll_counter: Counter,
+    req_counter: Counter,
+    req_err_counter: Counter,
+    req_retry_counter: Counter,
+    slow_request_counter: Counter,
+    job_builds_gauge: IntGaugeVec,
+    job_reqtime_gauge: IntGaugeVec,
+    job_build_duration_histogram: HistogramVec,
+}
+
+fn register_metrics(opts: &Opts) -> Metrics {
+    Metrics {
+        poll_counter: register_counter!("poll_cycle_counter", "Number of poll cycles done")
+            .unwrap(),
+        req_counter: register_counter!(
+            "req_counter",
+            "Number of total Jenkins API HTTPS requests done"
+        )
+        .unwrap(),
+        req_err_counter: register_counter!(
+            "req_err_counter",
+            "Number of Jenkins API HTTS requests that ended in error"
+        )
+        .unwrap(),
+        req_retry_counter: register_counter!(
+            "req_retry_counter",
+            "Number of times a Jenkins API request was retried after a transport error"
+        )
+        .unwrap(),
+        slow_request_counter: register_counter!(
+            "slow_request_counter",
+            "Number of Jenkins API requests that exceeded slow_request_warn_ms"
+        )
+        .unwrap(),
+        job_builds_gauge: register_int_gauge_vec!(
+            "jenkins_job_builds",
+            "Number of builds in the inspected window, by job and result",
+            &["job", "result"]
+        )
+        .unwrap(),
+        job_reqtime_gauge: register_int_gauge_vec!(
+            "job_reqtime_ms",
+            "How long the last Jenkins API request for a job took, including retries, in milliseconds",
+            &["job"]
+        )
+        .unwrap(),
+        job_build_duration_histogram: register_histogram_vec!(
+            "jenkins_job_build_duration_seconds",
+            "Build duration, in seconds, for builds in the inspected window",
+            &["job"],
+            opts.duration_buckets.clone()
+        )
+        .unwrap(),
+    }
+}
+
+const RESULT_KINDS: [&str; 4] = ["total", "success", "failure", "unstable"];
+
+/// Fills `metrics` with one poll cycle's worth of data. Returns `true` if every job's request
+/// succeeded.
+fn fill_metrics(opts: &Opts, metrics: &Metrics, new_data: &AllGaugeData) -> bool {
+    for job in &opts.jobs {
+        for result in &RESULT_KINDS {
+            if opts.verbose > 4 {
+                eprintln!("fill job: {} result: {}", &job, &result);
+            }
+            let d = new_data.gauges[&job.to_string()][&result.to_string()];
+            metrics
+                .job_builds_gauge
+                .with_label_values(&[job, result])
+                .set(d);
+        }
+        let reqtime = new_data.gauges[&job.to_string()]["job_reqtime_ms"];
+        metrics
+            .job_reqtime_gauge
+            .with_label_values(&[job])
+            .set(reqtime);
+
+        for duration_sec in &new_data.durations[&job.to_string()] {
+            metrics
+                .job_build_duration_histogram
+                .with_label_values(&[job])
+                .observe(*duration_sec);
+        }
+    }
+    metrics.req_err_counter.inc_by(new_data.req_err_counter as f64);
+    metrics.req_counter.inc_by(new_data.req_counter as f64);
+    metrics
+        .req_retry_counter
+        .inc_by(new_data.req_retry_counter as f64);
+    metrics
+        .slow_request_counter
+        .inc_by(new_data.slow_request_counter as f64);
+
+    metrics.poll_counter.inc();
+
+    new_data.req_err_counter == 0
+}
+
+/// Runs a single poll cycle, encodes the registered metrics in the Prometheus text exposition
+/// format, writes them to `opts.snapshot_file` (atomically, via a temp file + rename) or to
+/// stdout, and exits the process with a nonzero status if any job's request failed.
+fn run_oneshot(opts: &Opts) -> ! {
+    let metrics = register_metrics(opts);
+    let new_data = get_all_gauge_data(opts);
+    if opts.verbose > 3 {
+        eprintln!("d: {:#?}", &new_data);
+    }
+    let ok = fill_metrics(opts, &metrics, &new_data);
+
+    let encoder = TextEncoder::new();
+    let metric_families = prometheus_exporter::prometheus::gather();
+    let mut buffer = Vec::new();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+
+    if let Some(path) = &opts.snapshot_file {
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, &buffer).unwrap();
+        std::fs::rename(&tmp_path, path).unwrap();
+    } else {
+        std::io::stdout().write_all(&buffer).unwrap();
+    }
+
+    std::process::exit(if ok { 0 } else { 1 });
+}
+
+/// Hidden `generate-completions <shell>` and `generate-man` paths, driven straight off the
+/// `Opts` clap derive so packagers get completions/a man page from the single source of truth
+/// for the CLI flags. This has to be checked before `Opts::parse()` runs, since `jobs` is
+/// `required = true` and these paths don't take a `jobs` argument at all.
+fn maybe_generate_docs_and_exit() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.get(1).map(String::as_str) == Some("generate-completions") {
+        let shell = args.get(2).map(String::as_str).unwrap_or("");
+        let mut app = Opts::into_app();
+        let name = app.get_name().to_string();
+        let mut stdout = std::io::stdout();
+        match shell {
+            "bash" => clap_generate::generate::<Bash, _>(&mut app, name, &mut stdout),
+            "zsh" => clap_generate::generate::<Zsh, _>(&mut app, name, &mut stdout),
+            "fish" => clap_generate::generate::<Fish, _>(&mut app, name, &mut stdout),
+            "powershell" => clap_generate::generate::<PowerShell, _>(&mut app, name, &mut stdout),
+            other => {
+                eprintln!(
+                    "Unknown shell '{}': expected one of bash, zsh, fish, powershell",
+                    other
+                );
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    if args.get(1).map(String::as_str) == Some("generate-man") {
+        let app = Opts::into_app();
+        let man = clap_mangen::Man::new(app);
+        man.render(&mut std::io::stdout()).unwrap();
+        std::process::exit(0);
+    }
+}
+
 fn main() {
+    maybe_generate_docs_and_exit();
+
     let opts: Opts = Opts::parse();
 
     let opts = if let Ok(data) = std::fs::read_to_string(&opts.jobs[0]) {
@@ -187,6 +541,10 @@ fn main() {
         println!("{}", data);
     }
 
+    if opts.oneshot || opts.snapshot_file.is_some() {
+        run_oneshot(&opts);
+    }
+
     let exporter = prometheus_exporter::start(opts.bind_to.clone()).unwrap();
     println!(
         "Started Prometheus exporter on {}, monitoring {} jobs on {} with {} seconds poll interval",
@@ -196,80 +554,36 @@ fn main() {
         &opts.poll_interval_sec
     );
 
-    let poll_counter =
-        register_counter!("poll_cycle_counter", "Number of poll cycles done").unwrap();
-    let req_counter = register_counter!(
-        "req_counter",
-        "Number of total Jenkins API HTTPS requests done"
-    )
-    .unwrap();
-    let req_err_counter = register_counter!(
-        "req_err_counter",
-        "Number of Jenkins API HTTS requests that ended in error"
-    )
-    .unwrap();
-
-    let mut gauges: HashMap<String, HashMap<String, GenericGauge<AtomicI64>>> = HashMap::new();
-    let gauge_info = vec![
-        ("total", "last builds total"),
-        ("success", "last builds with SUCCESS"),
-        ("failure", "last builds with FAILURE"),
-        ("unstable", "last builds with UNSTABLE"),
-        (
-            "job_reqtime_ms",
-            "how long the last Jenkins API request took",
-        ),
-    ];
-
-    for job in &opts.jobs {
-        for (gauge_name, gauge_help) in &gauge_info {
-            let metric_name = job.clone().replace("-", "_");
-            let new_gauge = register_int_gauge!(
-                format!("{}_{}", &metric_name, gauge_name),
-                format!("{} {}", &job, &gauge_help)
-            )
-            .unwrap();
-            gauges
-                .entry(job.to_string())
-                .or_insert(HashMap::new())
-                .insert(gauge_name.to_string(), new_gauge);
-        }
-    }
+    let metrics = register_metrics(&opts);
 
     let mut wait_sec: u64 = 0;
     loop {
         let opts_clone = opts.clone();
+        // Time the poll work itself, independently of the wait that runs alongside it.
         let handle = std::thread::spawn(move || {
-            let opts = opts_clone;
-            let new_data = get_all_gauge_data(&opts);
-            if opts.verbose > 3 {
-                eprintln!("d: {:#?}", &new_data);
-            }
-            new_data
+            let cycle_start = SystemTime::now();
+            let new_data = get_all_gauge_data(&opts_clone);
+            (new_data, cycle_start.elapsed())
         });
 
         let guard = exporter.wait_duration(std::time::Duration::from_secs(wait_sec));
-        let new_data = handle.join().unwrap();
-
-        for job in &opts.jobs {
-            for (gauge_name, _) in &gauge_info {
-                /*(
-                # we pre-created the hashmaps on the left, and we expect
-                # the same data from hashmaps on the right,
-                # if the data is not there this is a terminal event
-                */
-                if opts.verbose > 4 {
-                    eprintln!("fill job: {} gauge: {}", &job, &gauge_name);
-                }
-                let d = new_data.gauges[&job.to_string()][&gauge_name.to_string()];
+        let (new_data, cycle_elapsed) = handle.join().unwrap();
+        if opts.verbose > 3 {
+            eprintln!("d: {:#?}", &new_data);
+        }
 
-                gauges[&job.to_string()][&gauge_name.to_string()].set(d);
+        if let Ok(cycle_elapsed) = cycle_elapsed {
+            let cycle_elapsed_sec = cycle_elapsed.as_secs_f64();
+            if cycle_elapsed_sec > 0.8 * opts.poll_interval_sec as f64 {
+                eprintln!(
+                    "WARNING: poll cycle took {:.1}s, approaching poll_interval_sec ({}s)",
+                    cycle_elapsed_sec, opts.poll_interval_sec
+                );
             }
         }
-        req_err_counter.inc_by(new_data.req_err_counter as f64);
-        req_counter.inc_by(new_data.req_counter as f64);
 
-        poll_counter.inc();
+        fill_metrics(&opts, &metrics, &new_data);
+
         drop(guard);
         wait_sec = opts.poll_interval_sec;
     }